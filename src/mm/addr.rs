@@ -0,0 +1,112 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::ops::Add;
+
+/// Bits a byte address is shifted by to get a 4 KiB frame/page index.
+const PAGE_SHIFT: usize = 12;
+
+/// A byte address in physical memory.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) struct PhysAddr(usize);
+
+impl PhysAddr {
+    pub const fn new(addr: usize) -> Self {
+        PhysAddr(addr)
+    }
+
+    pub const fn as_usize(self) -> usize {
+        self.0
+    }
+
+    /// The frame this address falls in.
+    pub const fn containing_frame(self) -> PhysFrame {
+        PhysFrame(self.0 >> PAGE_SHIFT)
+    }
+}
+
+/// A 4 KiB physical frame, identified by its frame number.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) struct PhysFrame(usize);
+
+impl PhysFrame {
+    pub const fn new(frame: usize) -> Self {
+        PhysFrame(frame)
+    }
+
+    pub const fn start_address(self) -> PhysAddr {
+        PhysAddr(self.0 << PAGE_SHIFT)
+    }
+
+    pub const fn index(self) -> usize {
+        self.0
+    }
+}
+
+impl Add<usize> for PhysFrame {
+    type Output = PhysFrame;
+
+    fn add(self, rhs: usize) -> PhysFrame {
+        PhysFrame(self.0 + rhs)
+    }
+}
+
+/// A byte address in virtual memory.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) struct VirtAddr(usize);
+
+impl VirtAddr {
+    pub const fn new(addr: usize) -> Self {
+        VirtAddr(addr)
+    }
+
+    pub const fn as_usize(self) -> usize {
+        self.0
+    }
+
+    /// The page this address falls in.
+    pub const fn containing_page(self) -> VirtPage {
+        VirtPage(self.0 >> PAGE_SHIFT)
+    }
+}
+
+/// A 4 KiB virtual page, identified by its page number.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) struct VirtPage(usize);
+
+impl VirtPage {
+    pub const fn new(page: usize) -> Self {
+        VirtPage(page)
+    }
+
+    pub const fn containing(addr: VirtAddr) -> Self {
+        addr.containing_page()
+    }
+
+    pub const fn start_address(self) -> VirtAddr {
+        VirtAddr(self.0 << PAGE_SHIFT)
+    }
+
+    pub const fn index(self) -> usize {
+        self.0
+    }
+}
+
+impl Add<usize> for VirtPage {
+    type Output = VirtPage;
+
+    fn add(self, rhs: usize) -> VirtPage {
+        VirtPage(self.0 + rhs)
+    }
+}
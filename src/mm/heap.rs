@@ -0,0 +1,172 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Leading `::` resolves to the `alloc` crate unambiguously: plain `alloc`
+// would clash with the `core::alloc` module name bound below.
+use ::alloc::collections::BTreeMap;
+use core::{alloc, mem, ptr, ptr::NonNull};
+
+use super::vm::VirtualMemory;
+
+/// Pages requested from `VirtualMemory` each time the arena runs dry.
+const ARENA_PAGES: usize = 16;
+
+#[repr(C)]
+struct FreeBlock {
+    size: usize,
+    next: Option<NonNull<FreeBlock>>,
+}
+
+/// A free-list allocator with block splitting and coalescing, backed by
+/// whole pages obtained from a [`VirtualMemory`] on demand.
+///
+/// Free blocks are kept in a singly linked list ordered by address, which
+/// lets [`Heap::push_free`] merge a freed or leftover block with its
+/// immediate neighbours in constant time.
+pub(super) struct Heap {
+    head: Option<NonNull<FreeBlock>>,
+    /// Exact `[start, end)` ranges handed to this heap by [`Heap::grow`],
+    /// keyed by `start`, so [`Heap::owns`] doesn't mistake a gap between two
+    /// growths (e.g. an unrelated directly-paged allocation) for heap space.
+    arenas: BTreeMap<usize, usize>,
+}
+
+unsafe impl Send for Heap {}
+
+impl Heap {
+    pub const fn empty() -> Self {
+        Heap {
+            head: None,
+            arenas: BTreeMap::new(),
+        }
+    }
+
+    /// Whether `ptr` falls within a page range previously handed to this
+    /// heap by [`Heap::grow`].
+    pub fn owns(&self, ptr: *mut u8) -> bool {
+        let addr = ptr as usize;
+        self.arenas
+            .range(..=addr)
+            .next_back()
+            .is_some_and(|(&start, &end)| addr >= start && addr < end)
+    }
+
+    pub fn alloc(&mut self, vm: &VirtualMemory, layout: alloc::Layout) -> *mut u8 {
+        let size = layout.size().max(mem::size_of::<FreeBlock>());
+        let align = layout.align().max(mem::align_of::<FreeBlock>());
+
+        loop {
+            if let Some(ptr) = self.take(size, align) {
+                return ptr;
+            }
+
+            let pages = ((size - 1) >> 12) + 1;
+            if !self.grow(vm, pages.max(ARENA_PAGES)) {
+                return ptr::null_mut();
+            }
+        }
+    }
+
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: alloc::Layout) {
+        let size = layout.size().max(mem::size_of::<FreeBlock>());
+        self.push_free(ptr as usize, size);
+    }
+
+    /// Finds the first free block that fits `size` aligned to `align`,
+    /// returning the unused head and tail to the free list.
+    fn take(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        let mut prev: Option<NonNull<FreeBlock>> = None;
+        let mut cur = self.head;
+        while let Some(c) = cur {
+            let start = c.as_ptr() as usize;
+            let aligned = (start + align - 1) & !(align - 1);
+            let padding = aligned - start;
+            let block_size = unsafe { c.as_ref().size };
+
+            if block_size >= size + padding {
+                let next = unsafe { c.as_ref().next };
+                match prev {
+                    Some(mut p) => unsafe { p.as_mut().next = next },
+                    None => self.head = next,
+                }
+
+                if padding >= mem::size_of::<FreeBlock>() {
+                    unsafe { self.push_free(start, padding) };
+                }
+                let remainder = block_size - padding - size;
+                if remainder >= mem::size_of::<FreeBlock>() {
+                    unsafe { self.push_free(aligned + size, remainder) };
+                }
+
+                return Some(aligned as *mut u8);
+            }
+
+            prev = cur;
+            cur = unsafe { c.as_ref().next };
+        }
+
+        None
+    }
+
+    /// Reserves `pages` fresh pages from `vm` and returns them to the free
+    /// list as a single block.
+    fn grow(&mut self, vm: &VirtualMemory, pages: usize) -> bool {
+        let Some(page_start) = vm.allocate(pages) else {
+            return false;
+        };
+
+        let addr = page_start.start_address().as_usize();
+        let size = pages << 12;
+        self.arenas.insert(addr, addr + size);
+        unsafe { self.push_free(addr, size) };
+
+        true
+    }
+
+    /// Inserts the block `[addr, addr + size)` into the free list, keeping
+    /// it ordered by address, and coalesces it with adjacent neighbours.
+    unsafe fn push_free(&mut self, addr: usize, size: usize) {
+        let mut block = NonNull::new(addr as *mut FreeBlock).unwrap();
+
+        let mut prev: Option<NonNull<FreeBlock>> = None;
+        let mut cur = self.head;
+        while let Some(c) = cur {
+            if c.as_ptr() as usize > addr {
+                break;
+            }
+            prev = Some(c);
+            cur = c.as_ref().next;
+        }
+
+        block.as_mut().size = size;
+        block.as_mut().next = cur;
+        match prev {
+            Some(mut p) => p.as_mut().next = Some(block),
+            None => self.head = Some(block),
+        }
+
+        if let Some(next) = block.as_ref().next {
+            if block.as_ptr() as usize + block.as_ref().size == next.as_ptr() as usize {
+                block.as_mut().size += next.as_ref().size;
+                block.as_mut().next = next.as_ref().next;
+            }
+        }
+        if let Some(mut p) = prev {
+            if p.as_ptr() as usize + p.as_ref().size == block.as_ptr() as usize {
+                p.as_mut().size += block.as_ref().size;
+                p.as_mut().next = block.as_ref().next;
+            }
+        }
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::addr::PhysFrame;
+
+/// Largest block order the buddy allocator hands out, `1 << MAX_ORDER`
+/// frames (4 MiB), matching the hardware superpage size.
+const MAX_ORDER: usize = 10;
+
+/// Frames addressable by the allocator: `1 << 20`, as many pages as x86's
+/// two-level page tables can address.
+const FRAME_COUNT: usize = 0x10_0000;
+
+const NIL: u32 = u32::MAX;
+
+pub(crate) static PHYS_MEM: spin::Mutex<PhysMem> = spin::Mutex::new(PhysMem::new());
+
+/// A binary buddy allocator over physical frames.
+///
+/// `free_lists[k]` is the head of a singly linked list of free, naturally
+/// aligned blocks of `1 << k` frames. The link for a free block's head frame
+/// is stored in `next`, indexed by frame number, so the structure needs no
+/// heap of its own.
+pub(super) struct PhysMem {
+    free_lists: [u32; MAX_ORDER + 1],
+    next: [u32; FRAME_COUNT],
+}
+
+impl PhysMem {
+    pub const fn new() -> Self {
+        PhysMem {
+            free_lists: [NIL; MAX_ORDER + 1],
+            next: [NIL; FRAME_COUNT],
+        }
+    }
+
+    /// Finds and reserves a contiguous run covering `pages` frames, rounded
+    /// up to the allocator's next block order. Returns `None` if `pages`
+    /// exceeds `1 << MAX_ORDER` or no block is free.
+    pub fn find_free(&mut self, pages: usize) -> Option<PhysFrame> {
+        let order = order_for(pages);
+        let frame = self.pop(order)?;
+        self.trim(frame, order, pages);
+        Some(PhysFrame::new(frame))
+    }
+
+    /// Marks `pages` frames starting at `frame_start` as used.
+    ///
+    /// The buddy allocator already unlinks blocks from their free list when
+    /// [`PhysMem::find_free`] hands them out, so this is a no-op kept so
+    /// callers don't need to special-case the allocator they're talking to.
+    pub fn mark_used(&mut self, _frame_start: PhysFrame, _pages: usize) {}
+
+    /// Returns `pages` frames starting at `frame_start` to the allocator,
+    /// splitting the range into maximal aligned blocks and coalescing each
+    /// with its buddy where possible.
+    pub fn mark_free(&mut self, frame_start: PhysFrame, pages: usize) {
+        let mut frame = frame_start.index();
+        let mut remaining = pages;
+        while remaining > 0 {
+            let align_order = if frame == 0 {
+                MAX_ORDER
+            } else {
+                (frame.trailing_zeros() as usize).min(MAX_ORDER)
+            };
+            let mut order = align_order;
+            while (1usize << order) > remaining {
+                order -= 1;
+            }
+
+            self.push_coalesced(frame, order);
+            frame += 1 << order;
+            remaining -= 1 << order;
+        }
+    }
+
+    /// Finds and reserves a run of `pages` frames whose physical start is a
+    /// multiple of `align_frames` and which does not straddle a
+    /// `boundary_frames`-aligned limit, as DMA-capable hardware requires.
+    ///
+    /// Unlike [`PhysMem::find_free`], this walks the free lists of blocks
+    /// large enough to hold `pages` looking for one that already satisfies
+    /// both constraints, skipping past ones that don't.
+    pub fn find_free_constrained(
+        &mut self,
+        pages: usize,
+        align_frames: usize,
+        boundary_frames: usize,
+    ) -> Option<PhysFrame> {
+        for order in order_for(pages)..=MAX_ORDER {
+            let mut cur = self.free_lists[order];
+            while cur != NIL {
+                let frame = cur as usize;
+                let next = self.next[frame];
+
+                if frame % align_frames == 0 && fits_boundary(frame, pages, boundary_frames) {
+                    self.remove(frame, order);
+                    self.trim(frame, order, pages);
+                    return Some(PhysFrame::new(frame));
+                }
+
+                cur = next;
+            }
+        }
+
+        None
+    }
+
+    /// Returns the unneeded tail of a just-popped `1 << order` block to the
+    /// free lists, so callers of [`PhysMem::find_free`] and
+    /// [`PhysMem::find_free_constrained`] only hand out `pages` frames
+    /// instead of leaking the rest of the block they rounded up to.
+    fn trim(&mut self, frame: usize, order: usize, pages: usize) {
+        let extra = (1 << order) - pages;
+        if extra > 0 {
+            self.mark_free(PhysFrame::new(frame + pages), extra);
+        }
+    }
+
+    /// Pops a free block of exactly `order`, splitting the next larger
+    /// order if none is free at `order` itself.
+    fn pop(&mut self, order: usize) -> Option<usize> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        if self.free_lists[order] != NIL {
+            let frame = self.free_lists[order] as usize;
+            self.remove(frame, order);
+            return Some(frame);
+        }
+
+        let frame = self.pop(order + 1)?;
+        // the upper half of the block we just split off becomes its buddy
+        self.push(frame + (1 << order), order);
+        Some(frame)
+    }
+
+    /// Inserts `1 << order` frames starting at `frame`, merging with its
+    /// buddy and promoting to the next order for as long as that succeeds.
+    fn push_coalesced(&mut self, mut frame: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = frame ^ (1 << order);
+            if !self.remove(buddy, order) {
+                break;
+            }
+            frame = frame.min(buddy);
+            order += 1;
+        }
+        self.push(frame, order);
+    }
+
+    fn push(&mut self, frame: usize, order: usize) {
+        self.next[frame] = self.free_lists[order];
+        self.free_lists[order] = frame as u32;
+    }
+
+    /// Removes `frame` from the free list of `order`, if it is there.
+    fn remove(&mut self, frame: usize, order: usize) -> bool {
+        let mut cur = self.free_lists[order];
+        let mut prev = NIL;
+        while cur != NIL {
+            if cur as usize == frame {
+                if prev == NIL {
+                    self.free_lists[order] = self.next[cur as usize];
+                } else {
+                    self.next[prev as usize] = self.next[cur as usize];
+                }
+                return true;
+            }
+            prev = cur;
+            cur = self.next[cur as usize];
+        }
+        false
+    }
+}
+
+/// Smallest order `k` with `1 << k >= pages`.
+fn order_for(pages: usize) -> usize {
+    pages.max(1).next_power_of_two().trailing_zeros() as usize
+}
+
+/// Whether `[frame, frame + pages)` stays within a single
+/// `boundary_frames`-aligned region, or `boundary_frames` is `0` (no limit).
+fn fits_boundary(frame: usize, pages: usize, boundary_frames: usize) -> bool {
+    boundary_frames == 0 || frame / boundary_frames == (frame + pages - 1) / boundary_frames
+}
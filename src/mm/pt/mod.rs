@@ -0,0 +1,115 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod sv39;
+mod x86;
+
+use core::ops::BitOr;
+
+use super::addr::{PhysFrame, VirtPage};
+
+#[cfg(not(target_arch = "riscv64"))]
+pub(super) use self::x86::X86 as ActiveMapper;
+#[cfg(target_arch = "riscv64")]
+pub(super) use self::sv39::Sv39 as ActiveMapper;
+
+/// Byte size of a leaf page table entry's mapping, i.e. the smallest unit
+/// [`super::vm::VirtualMemory`] hands out.
+pub(super) const GRANULARITY: usize = 4096;
+
+/// Frames covered by one x86 superpage (PSE) entry: 4 MiB at 4 KiB frames.
+pub(super) const SUPERPAGE_FRAMES: usize = 1024;
+
+/// Pages the active architecture's page tables can address, sized to its
+/// VPN width rather than shared across backends: x86's two 10-bit levels
+/// give a 20-bit VPN, while Sv39's three 9-bit levels give a 27-bit one.
+#[cfg(not(target_arch = "riscv64"))]
+pub(super) const ADDRESSABLE_PAGES: usize = 1 << 20;
+#[cfg(target_arch = "riscv64")]
+pub(super) const ADDRESSABLE_PAGES: usize = 1 << 27;
+
+/// Mapping permissions, independent of how a given architecture's page
+/// table entries encode them.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub(super) struct Flags(u8);
+
+impl Flags {
+    pub const WRITABLE: Flags = Flags(1 << 0);
+    pub const EXECUTABLE: Flags = Flags(1 << 1);
+
+    pub const fn contains(self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Flags {
+    type Output = Flags;
+
+    fn bitor(self, rhs: Flags) -> Flags {
+        Flags(self.0 | rhs.0)
+    }
+}
+
+/// A page-table walker for one CPU architecture.
+///
+/// Implementors own the root table and translate between its arch-specific
+/// levels and index width and the flat [`VirtPage`]/[`PhysFrame`] the rest
+/// of `mm` deals in, so [`super::vm::VirtualMemory`] never has to know how
+/// many levels a page table has or how wide each level's index is.
+pub(super) trait Mapper {
+    /// The root table this mapper walks, read with [`Mapper::walk_create`].
+    type Table;
+
+    fn root(&mut self) -> &mut Self::Table;
+
+    /// Maps `virt` to `phys` with `flags`, creating intermediate tables as
+    /// needed. Panics if `virt` is already mapped.
+    fn map(&mut self, virt: VirtPage, phys: PhysFrame, flags: Flags);
+
+    /// Removes the mapping for `virt` and returns the frame it pointed to.
+    /// Panics if `virt` is not mapped.
+    fn unmap(&mut self, virt: VirtPage) -> PhysFrame;
+
+    /// Looks up the frame `virt` is mapped to, if any.
+    fn translate(&self, virt: VirtPage) -> Option<PhysFrame>;
+
+    /// Attempts to map `virt` to `phys` as a single large-page entry instead
+    /// of one leaf per frame. Returns `false` (and maps nothing) if the
+    /// architecture has no such entry, `virt`/`phys` aren't aligned to it,
+    /// or the relevant slot is already in use; callers should fall back to
+    /// [`Mapper::map`] one frame at a time.
+    fn map_superpage(&mut self, virt: VirtPage, phys: PhysFrame, flags: Flags) -> bool;
+
+    /// How many consecutive pages starting at `virt` callers may treat as
+    /// resolved by the same page table entry as `virt` itself, letting them
+    /// skip scanning each page individually. Always at least `1`: x86
+    /// returns more inside a superpage or where no second-level table
+    /// exists yet, while Sv39 always returns `1`.
+    fn run_len(&self, virt: VirtPage) -> usize;
+
+    /// Walks down to the leaf-level table for `virt`, the one holding
+    /// `virt`'s own entry, creating and zeroing intermediate tables that
+    /// don't exist yet. Neither backend reads `level`: the walk always ends
+    /// at the leaf-level table.
+    fn walk_create(&mut self, virt: VirtPage, level: usize) -> &mut Self::Table;
+
+    /// Creates and zeros the intermediate table(s) down to `virt`'s leaf
+    /// level if they don't exist yet, the same as [`Mapper::walk_create`]
+    /// would in the course of a [`Mapper::map`] — except a new frame is
+    /// zeroed directly by its physical address instead of through
+    /// [`super::vm::VirtualMemory::with_temp_mapping`]. This is what
+    /// bootstraps the one mapping (`VirtualMemory`'s scratch page) that
+    /// `with_temp_mapping` itself depends on, so it cannot use it.
+    fn ensure_table(&mut self, virt: VirtPage);
+}
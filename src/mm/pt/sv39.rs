@@ -0,0 +1,227 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::ops::{Index, IndexMut};
+
+use super::{Flags, Mapper, GRANULARITY};
+use crate::mm::addr::{PhysFrame, VirtPage};
+
+/// Entries per table level: a 9-bit VPN index into a 4 KiB table of 8-byte
+/// entries.
+const ENTRIES: usize = 512;
+
+/// Sv39 has three 9-bit levels (VPN[2], VPN[1], VPN[0]) above the 12-bit
+/// page offset.
+const LEVELS: usize = 3;
+
+/// The root of the current address space's three-level page table, set up
+/// by `init_virt_mem` before any mapping is made.
+pub(super) static mut ROOT: *mut PageTable = core::ptr::null_mut();
+
+#[repr(transparent)]
+pub(super) struct PageTableEntry(u64);
+
+impl PageTableEntry {
+    const VALID: u64 = 1 << 0;
+    const READABLE: u64 = 1 << 1;
+    const WRITABLE: u64 = 1 << 2;
+    const EXECUTABLE: u64 = 1 << 3;
+    const PPN_SHIFT: u32 = 10;
+
+    pub fn free(&self) -> bool {
+        self.0 & Self::VALID == 0
+    }
+
+    /// Whether this entry points at the next-level table: valid with all of
+    /// R/W/X clear, the encoding Sv39 reserves for non-leaf entries.
+    fn is_table(&self) -> bool {
+        self.0 & Self::VALID != 0
+            && self.0 & (Self::READABLE | Self::WRITABLE | Self::EXECUTABLE) == 0
+    }
+
+    fn address(&self) -> usize {
+        ((self.0 >> Self::PPN_SHIFT) << 12) as usize
+    }
+
+    pub fn map_table(&mut self, frame: usize) {
+        self.0 = ((frame as u64) << Self::PPN_SHIFT) | Self::VALID;
+    }
+
+    pub fn map_leaf(&mut self, frame: usize, flags: Flags) {
+        let mut bits = ((frame as u64) << Self::PPN_SHIFT) | Self::VALID | Self::READABLE;
+        if flags.contains(Flags::WRITABLE) {
+            bits |= Self::WRITABLE;
+        }
+        if flags.contains(Flags::EXECUTABLE) {
+            bits |= Self::EXECUTABLE;
+        }
+        self.0 = bits;
+    }
+
+    pub fn unmap(&mut self) -> usize {
+        let frame = self.address() >> 12;
+        self.0 = 0;
+        frame
+    }
+}
+
+/// Reserves a physical frame for a new page table and zeroes it, so a
+/// freshly created table never starts out with stale, garbage entries.
+fn new_table_frame() -> PhysFrame {
+    let frame = crate::mm::PHYS_MEM
+        .lock()
+        .find_free(1)
+        .expect("out of memory for page tables");
+    crate::mm::vm::VirtualMemory.with_temp_mapping(frame, |page| page.fill(0));
+    frame
+}
+
+/// Reserves a physical frame for a new page table and zeroes it directly by
+/// its physical address rather than through a temporary virtual mapping.
+/// Used only by [`Sv39::ensure_table`], which bootstraps the scratch-page
+/// table [`new_table_frame`]'s own zeroing depends on.
+fn new_table_frame_direct() -> PhysFrame {
+    let frame = crate::mm::PHYS_MEM
+        .lock()
+        .find_free(1)
+        .expect("out of memory for page tables");
+    unsafe {
+        core::ptr::write_bytes(frame.start_address().as_usize() as *mut u8, 0, GRANULARITY);
+    }
+    frame
+}
+
+#[repr(transparent)]
+pub(super) struct PageTable([PageTableEntry; ENTRIES]);
+
+impl PageTable {
+    /// `virt`'s VPN at `level`, where `0` is the leaf level closest to the
+    /// page offset and `LEVELS - 1` is the one below the root.
+    fn vpn(virt: VirtPage, level: usize) -> usize {
+        (virt.index() >> (9 * level)) & 0x1FF
+    }
+
+    /// The next-level table `index` points to, or `None` if that slot is
+    /// unmapped.
+    pub fn table(&mut self, index: usize) -> Option<&mut PageTable> {
+        if !self.0[index].is_table() {
+            return None;
+        }
+        Some(unsafe { &mut *(self.0[index].address() as *mut PageTable) })
+    }
+
+    /// The next-level table `index` points to, allocating a fresh frame for
+    /// it if the slot is empty.
+    pub fn table_create(&mut self, index: usize) -> &mut PageTable {
+        if self.0[index].free() {
+            let frame = new_table_frame();
+            self.0[index].map_table(frame.index());
+        }
+        self.table(index).unwrap()
+    }
+}
+
+impl Index<usize> for PageTable {
+    type Output = PageTableEntry;
+
+    fn index(&self, index: usize) -> &PageTableEntry {
+        &self.0[index]
+    }
+}
+
+impl IndexMut<usize> for PageTable {
+    fn index_mut(&mut self, index: usize) -> &mut PageTableEntry {
+        &mut self.0[index]
+    }
+}
+
+/// The riscv64 Sv39 page table walker: three 9-bit levels over a 12-bit
+/// page offset.
+///
+/// Visible to all of `mm`, not just `pt`, since `pt::mod` re-exports it
+/// (via `ActiveMapper`) at that wider scope.
+pub(in crate::mm) struct Sv39;
+
+impl Mapper for Sv39 {
+    type Table = PageTable;
+
+    fn root(&mut self) -> &mut PageTable {
+        unsafe { &mut *ROOT }
+    }
+
+    fn map(&mut self, virt: VirtPage, phys: PhysFrame, flags: Flags) {
+        let table = self.walk_create(virt, 0);
+        let index = PageTable::vpn(virt, 0);
+        if !table[index].free() {
+            panic!("non-contiguous");
+        }
+        table[index].map_leaf(phys.index(), flags);
+    }
+
+    fn unmap(&mut self, virt: VirtPage) -> PhysFrame {
+        let mut table = self.root();
+        for level in (1..LEVELS).rev() {
+            table = table.table(PageTable::vpn(virt, level)).expect("already freed");
+        }
+
+        let index = PageTable::vpn(virt, 0);
+        if table[index].free() {
+            panic!("already freed");
+        }
+        PhysFrame::new(table[index].unmap())
+    }
+
+    fn translate(&self, virt: VirtPage) -> Option<PhysFrame> {
+        let mut table = unsafe { &mut *ROOT };
+        for level in (1..LEVELS).rev() {
+            table = table.table(PageTable::vpn(virt, level))?;
+        }
+
+        let index = PageTable::vpn(virt, 0);
+        if table[index].free() {
+            None
+        } else {
+            Some(PhysFrame::new(table[index].address() >> 12))
+        }
+    }
+
+    fn map_superpage(&mut self, _virt: VirtPage, _phys: PhysFrame, _flags: Flags) -> bool {
+        // megapages aren't wired up yet; callers fall back to per-frame maps
+        false
+    }
+
+    fn run_len(&self, _virt: VirtPage) -> usize {
+        1
+    }
+
+    fn walk_create(&mut self, virt: VirtPage, _level: usize) -> &mut PageTable {
+        let mut table = self.root();
+        for level in (1..LEVELS).rev() {
+            table = table.table_create(PageTable::vpn(virt, level));
+        }
+        table
+    }
+
+    fn ensure_table(&mut self, virt: VirtPage) {
+        let mut table = self.root();
+        for level in (1..LEVELS).rev() {
+            let index = PageTable::vpn(virt, level);
+            if table[index].free() {
+                let frame = new_table_frame_direct();
+                table[index].map_table(frame.index());
+            }
+            table = table.table(index).unwrap();
+        }
+    }
+}
@@ -0,0 +1,257 @@
+// Copyright 2024 Kevin Ludwig
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use core::ops::{Index, IndexMut};
+
+use super::{Flags, Mapper, GRANULARITY, SUPERPAGE_FRAMES};
+use crate::mm::addr::{PhysFrame, VirtPage};
+
+/// Entries per table level: a 10-bit index into a 4 KiB table of 4-byte
+/// entries.
+const ENTRIES: usize = 1024;
+
+/// The root of the current address space's two-level page table, set up by
+/// `init_virt_mem` before any mapping is made.
+pub(super) static mut ROOT: *mut PageTable = core::ptr::null_mut();
+
+#[repr(transparent)]
+pub(super) struct PageTableEntry(u32);
+
+impl PageTableEntry {
+    const PRESENT: u32 = 1 << 0;
+    const WRITABLE: u32 = 1 << 1;
+    /// Page Size bit: set on a top-level entry, it maps a 4 MiB superpage
+    /// directly instead of pointing at a second-level table.
+    const PAGE_SIZE: u32 = 1 << 7;
+
+    pub fn free(&self) -> bool {
+        self.0 & Self::PRESENT == 0
+    }
+
+    pub fn is_superpage(&self) -> bool {
+        self.0 & (Self::PRESENT | Self::PAGE_SIZE) == Self::PRESENT | Self::PAGE_SIZE
+    }
+
+    fn flags(&self) -> Flags {
+        if self.0 & Self::WRITABLE != 0 {
+            Flags::WRITABLE
+        } else {
+            Flags::default()
+        }
+    }
+
+    fn frame(&self) -> usize {
+        (self.0 >> 12) as usize
+    }
+
+    pub fn map(&mut self, frame: usize, flags: Flags) {
+        let mut bits = (frame << 12) as u32 | Self::PRESENT;
+        if flags.contains(Flags::WRITABLE) {
+            bits |= Self::WRITABLE;
+        }
+        self.0 = bits;
+    }
+
+    pub fn map_superpage(&mut self, frame: usize, flags: Flags) {
+        self.map(frame, flags);
+        self.0 |= Self::PAGE_SIZE;
+    }
+
+    pub fn unmap(&mut self) -> usize {
+        let frame = self.frame();
+        self.0 = 0;
+        frame
+    }
+}
+
+/// Reserves a physical frame for a new page table and zeroes it, so a
+/// freshly created table never starts out with stale, garbage entries.
+fn new_table_frame() -> PhysFrame {
+    let frame = crate::mm::PHYS_MEM
+        .lock()
+        .find_free(1)
+        .expect("out of memory for page tables");
+    crate::mm::vm::VirtualMemory.with_temp_mapping(frame, |page| page.fill(0));
+    frame
+}
+
+/// Reserves a physical frame for a new page table and zeroes it directly by
+/// its physical address rather than through a temporary virtual mapping.
+/// Used only by [`X86::ensure_table`], which bootstraps the scratch-page
+/// table [`new_table_frame`]'s own zeroing depends on.
+fn new_table_frame_direct() -> PhysFrame {
+    let frame = crate::mm::PHYS_MEM
+        .lock()
+        .find_free(1)
+        .expect("out of memory for page tables");
+    unsafe {
+        core::ptr::write_bytes(frame.start_address().as_usize() as *mut u8, 0, GRANULARITY);
+    }
+    frame
+}
+
+#[repr(transparent)]
+pub(super) struct PageTable([PageTableEntry; ENTRIES]);
+
+impl PageTable {
+    /// The next-level table `index` points to, or `None` if that slot is
+    /// unmapped or is itself a superpage leaf.
+    pub fn table(&mut self, index: usize) -> Option<&mut PageTable> {
+        if self.0[index].free() || self.0[index].is_superpage() {
+            return None;
+        }
+        Some(unsafe { &mut *(((self.0[index].0 & !0xFFF) as usize) as *mut PageTable) })
+    }
+
+    /// The next-level table `index` points to, allocating a fresh, zeroed
+    /// frame for it if the slot is empty.
+    pub fn table_create(&mut self, index: usize) -> &mut PageTable {
+        if self.0[index].free() {
+            let frame = new_table_frame();
+            self.0[index].map(frame.index(), Flags::WRITABLE);
+        }
+        self.table(index).unwrap()
+    }
+
+    /// Demotes the superpage at `index` into a freshly allocated
+    /// second-level table that reproduces the same mapping one frame at a
+    /// time, and returns it. Used to free a single page out of a superpage
+    /// run without disturbing the rest of it.
+    fn split_superpage(&mut self, index: usize) -> &mut PageTable {
+        let base_frame = self.0[index].frame();
+        let flags = self.0[index].flags();
+
+        let frame = new_table_frame();
+        self.0[index].map(frame.index(), Flags::WRITABLE);
+
+        let table = self.table(index).unwrap();
+        for i in 0..SUPERPAGE_FRAMES {
+            table.0[i].map(base_frame + i, flags);
+        }
+        table
+    }
+}
+
+impl Index<usize> for PageTable {
+    type Output = PageTableEntry;
+
+    fn index(&self, index: usize) -> &PageTableEntry {
+        &self.0[index]
+    }
+}
+
+impl IndexMut<usize> for PageTable {
+    fn index_mut(&mut self, index: usize) -> &mut PageTableEntry {
+        &mut self.0[index]
+    }
+}
+
+/// The x86 two-level, 10/10/12-bit page table walker.
+///
+/// Visible to all of `mm`, not just `pt`, since `pt::mod` re-exports it
+/// (via `ActiveMapper`) at that wider scope.
+pub(in crate::mm) struct X86;
+
+impl Mapper for X86 {
+    type Table = PageTable;
+
+    fn root(&mut self) -> &mut PageTable {
+        unsafe { &mut *ROOT }
+    }
+
+    fn map(&mut self, virt: VirtPage, phys: PhysFrame, flags: Flags) {
+        let page = virt.index();
+        let table = self.walk_create(virt, 0);
+        let entry = &mut table[page & 0x3FF];
+        if !entry.free() {
+            panic!("non-contiguous");
+        }
+        entry.map(phys.index(), flags);
+    }
+
+    fn unmap(&mut self, virt: VirtPage) -> PhysFrame {
+        let page = virt.index();
+        let top_index = page >> 10;
+        let root = self.root();
+
+        let table = if root[top_index].is_superpage() {
+            root.split_superpage(top_index)
+        } else {
+            root.table(top_index).expect("already freed")
+        };
+
+        let entry = &mut table[page & 0x3FF];
+        if entry.free() {
+            panic!("already freed");
+        }
+        PhysFrame::new(entry.unmap())
+    }
+
+    fn translate(&self, virt: VirtPage) -> Option<PhysFrame> {
+        let page = virt.index();
+        let top_index = page >> 10;
+        let root = unsafe { &mut *ROOT };
+
+        if root[top_index].is_superpage() {
+            return Some(PhysFrame::new(root[top_index].frame() + (page & 0x3FF)));
+        }
+
+        let table = root.table(top_index)?;
+        let entry = &table[page & 0x3FF];
+        if entry.free() {
+            None
+        } else {
+            Some(PhysFrame::new(entry.frame()))
+        }
+    }
+
+    fn map_superpage(&mut self, virt: VirtPage, phys: PhysFrame, flags: Flags) -> bool {
+        if virt.index() % SUPERPAGE_FRAMES != 0 || phys.index() % SUPERPAGE_FRAMES != 0 {
+            return false;
+        }
+
+        let top_index = virt.index() >> 10;
+        let root = self.root();
+        if !root[top_index].free() {
+            return false;
+        }
+
+        root[top_index].map_superpage(phys.index(), flags);
+        true
+    }
+
+    fn run_len(&self, virt: VirtPage) -> usize {
+        let top_index = virt.index() >> 10;
+        let root = unsafe { &mut *ROOT };
+        if root[top_index].is_superpage() || root[top_index].free() {
+            SUPERPAGE_FRAMES - (virt.index() % SUPERPAGE_FRAMES)
+        } else {
+            1
+        }
+    }
+
+    fn walk_create(&mut self, virt: VirtPage, _level: usize) -> &mut PageTable {
+        let index = virt.index() >> 10;
+        self.root().table_create(index)
+    }
+
+    fn ensure_table(&mut self, virt: VirtPage) {
+        let top_index = virt.index() >> 10;
+        let root = self.root();
+        if root[top_index].free() {
+            let frame = new_table_frame_direct();
+            root[top_index].map(frame.index(), Flags::WRITABLE);
+        }
+    }
+}
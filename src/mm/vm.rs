@@ -12,41 +12,93 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+// Leading `::` resolves to the `alloc` crate unambiguously: plain `alloc`
+// would clash with the `core::alloc` module name bound below.
+use ::alloc::collections::BTreeMap;
 use core::{alloc, ptr};
 
-use super::PHYS_MEM;
+use super::{
+    addr::{PhysAddr, PhysFrame, VirtAddr, VirtPage},
+    heap::Heap,
+    pt::{ActiveMapper, Flags, Mapper},
+    PHYS_MEM,
+};
+
+/// Backing store for small `alloc::` requests; large ones still go straight
+/// through [`VirtualMemory::allocate`] so they stay page-granular.
+static HEAP: spin::Mutex<Heap> = spin::Mutex::new(Heap::empty());
+
+/// The current address space's page-table walker, abstracted over the
+/// target architecture by [`Mapper`].
+static MAPPER: spin::Mutex<ActiveMapper> = spin::Mutex::new(ActiveMapper);
+
+/// Pages `AcpiHandler::map_physical_region` mapped on `VirtualMemory`'s
+/// behalf, keyed by the virtual address handed back in the
+/// `PhysicalMapping`, so `unmap_physical_region` can find what to free.
+static ACPI_MAPPINGS: spin::Mutex<BTreeMap<usize, (VirtPage, usize)>> =
+    spin::Mutex::new(BTreeMap::new());
+
+/// Page reserved for [`VirtualMemory::with_temp_mapping`], one past the
+/// highest index [`VirtualMemory::find_free`] ever hands out.
+const SCRATCH_PAGE: VirtPage = VirtPage::new(super::pt::ADDRESSABLE_PAGES - 1);
+
+/// Serializes users of [`SCRATCH_PAGE`]; held for the duration of a
+/// [`VirtualMemory::with_temp_mapping`] call.
+static SCRATCH_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+
+/// Unmaps [`SCRATCH_PAGE`] and releases [`SCRATCH_LOCK`] when dropped.
+struct TempMapping<'a> {
+    _lock: spin::MutexGuard<'a, ()>,
+}
+
+impl Drop for TempMapping<'_> {
+    fn drop(&mut self) {
+        // Bypasses `MAPPER`: `with_temp_mapping` is also called while a
+        // table walk already holds it (creating and zeroing a fresh table
+        // frame), and `ActiveMapper` carries no state of its own, so a
+        // second instance is just as valid a handle to the same tables.
+        ActiveMapper.unmap(SCRATCH_PAGE);
+    }
+}
 
 #[derive(Clone)]
 pub struct VirtualMemory;
 
 impl VirtualMemory {
     /// Maps frames to free pages
-    pub fn map(&self, frame_start: usize, frames: usize) -> Option<usize> {
+    pub fn map(&self, frame_start: PhysFrame, frames: usize) -> Option<VirtPage> {
         let page_start = self.find_free(frames)?;
-        for (page, frame) in
-            (page_start..page_start + frames).zip(frame_start..frame_start + frames)
-        {
-            let page_table = unsafe { &mut *super::pt::ROOT };
-            let page_table = page_table.table_create(page >> 10);
-            let page_table_entry = &mut page_table[page & 0x3FF];
-            if !page_table_entry.free() {
-                panic!("non-contiguous");
+        let mut mapper = MAPPER.lock();
+
+        let mut mapped = 0;
+        while mapped < frames {
+            let page = page_start + mapped;
+            let frame = frame_start + mapped;
+
+            // a run at least a superpage long and aligned to one maps in a
+            // single large entry instead of one leaf per frame
+            if frames - mapped >= super::pt::SUPERPAGE_FRAMES
+                && mapper.map_superpage(page, frame, Flags::WRITABLE)
+            {
+                mapped += super::pt::SUPERPAGE_FRAMES;
+                continue;
             }
 
-            page_table_entry.map(frame);
+            mapper.map(page, frame, Flags::WRITABLE);
+            mapped += 1;
         }
 
         Some(page_start)
     }
 
     /// Allocates free frames and maps them to free pages
-    pub fn allocate(&self, pages: usize) -> Option<usize> {
+    pub fn allocate(&self, pages: usize) -> Option<VirtPage> {
         self.allocate_contiguous(pages)
             .map(|(page_start, _)| page_start)
     }
 
     /// Allocates free frames and maps them to free pages
-    pub fn allocate_contiguous(&self, pages: usize) -> Option<(usize, usize)> {
+    pub fn allocate_contiguous(&self, pages: usize) -> Option<(VirtPage, PhysFrame)> {
         let frame_start;
         {
             let mut phys_mem = PHYS_MEM.lock();
@@ -58,60 +110,136 @@ impl VirtualMemory {
         Some((page_start, frame_start))
     }
 
-    /// Frees pages and frames
-    pub fn free(&self, page_start: usize, pages: usize) {
-        let mut phys_mem = PHYS_MEM.lock();
-        for page in page_start..page_start + pages {
-            let page_table = unsafe { &mut *super::pt::ROOT };
-            let page_table = page_table.table(page >> 10).expect("already freed");
-            let page_table_entry = &mut page_table[page & 0x3FF];
-            if page_table_entry.free() {
-                panic!("already freed")
-            }
+    /// Allocates free frames forming a run that is aligned to `align_frames`
+    /// and does not cross a `boundary_frames`-aligned limit, then maps them
+    /// to free pages. Returns both the virtual and physical start so
+    /// drivers can program hardware with the physical address.
+    pub fn allocate_contiguous_constrained(
+        &self,
+        pages: usize,
+        align_frames: usize,
+        boundary_frames: usize,
+    ) -> Option<(VirtPage, PhysFrame)> {
+        let frame_start;
+        {
+            let mut phys_mem = PHYS_MEM.lock();
+            frame_start =
+                phys_mem.find_free_constrained(pages, align_frames, boundary_frames)?;
+            phys_mem.mark_used(frame_start, pages);
+        }
+        let page_start = self.map(frame_start, pages)?;
 
-            let frame = page_table_entry.unmap();
-            phys_mem.mark_free(frame, 1);
+        Some((page_start, frame_start))
+    }
+
+    /// Allocates free frames, maps them to free pages, and zeroes their
+    /// contents before returning them, so a caller never observes a
+    /// previous owner's leftover data.
+    pub fn allocate_zeroed(&self, pages: usize) -> Option<VirtPage> {
+        let page_start = self.allocate(pages)?;
+        let slice = unsafe {
+            core::slice::from_raw_parts_mut(
+                page_start.start_address().as_usize() as *mut u8,
+                pages * super::pt::GRANULARITY,
+            )
+        };
+        slice.fill(0);
+
+        Some(page_start)
+    }
+
+    /// Maps `frame` into a reserved scratch page for the duration of `f`,
+    /// handing it a byte slice over the frame's contents, and unmaps it
+    /// again once `f` returns. This is the only safe way to touch a frame's
+    /// contents before it has a lasting mapping of its own, e.g. to zero a
+    /// freshly allocated page table.
+    ///
+    /// Deliberately maps through a standalone `ActiveMapper` rather than
+    /// `MAPPER.lock()`: a table walk already holding `MAPPER` is what calls
+    /// this to zero a table frame it just created, and re-locking here
+    /// would deadlock. `ensure_table` bootstraps `SCRATCH_PAGE`'s own
+    /// table via `Mapper::ensure_table` rather than the generic `map`, since
+    /// the generic path would try to zero that very table through this same
+    /// function before it exists.
+    pub(crate) fn with_temp_mapping<R>(&self, frame: PhysFrame, f: impl FnOnce(&mut [u8]) -> R) -> R {
+        let lock = SCRATCH_LOCK.lock();
+        ActiveMapper.ensure_table(SCRATCH_PAGE);
+        ActiveMapper.map(SCRATCH_PAGE, frame, Flags::WRITABLE);
+        let _unmap = TempMapping { _lock: lock };
+
+        let slice = unsafe {
+            core::slice::from_raw_parts_mut(
+                SCRATCH_PAGE.start_address().as_usize() as *mut u8,
+                super::pt::GRANULARITY,
+            )
+        };
+        f(slice)
+    }
+
+    /// Frees pages and frames
+    pub fn free(&self, page_start: VirtPage, pages: usize) {
+        // `mapper.unmap` must not run with `PHYS_MEM` held: freeing a page
+        // out of a superpage run demotes it first, which reserves a frame
+        // for the replacement table and would deadlock re-locking `PHYS_MEM`
+        // here.
+        let mut mapper = MAPPER.lock();
+        for page in page_start.index()..page_start.index() + pages {
+            let frame = mapper.unmap(VirtPage::new(page));
+            PHYS_MEM.lock().mark_free(frame, 1);
         }
     }
 
     /// Finds free pages
-    fn find_free(&self, pages: usize) -> Option<usize> {
+    fn find_free(&self, pages: usize) -> Option<VirtPage> {
+        let mapper = MAPPER.lock();
         let mut page_start = 1;
         let mut consecutive_pages = 0;
         while consecutive_pages < pages {
-            // not enough remaining pages
-            if page_start + pages > 0xFFFFF {
+            // not enough remaining pages before the reserved scratch page
+            if page_start + pages > super::pt::ADDRESSABLE_PAGES - 1 {
                 return None;
             }
-            let page = page_start + consecutive_pages;
+            let page = VirtPage::new(page_start + consecutive_pages);
+            // a superpage or an entirely unpopulated table resolves many
+            // pages at once, so skip the whole run instead of one at a time
+            let run = mapper.run_len(page);
 
-            let page_table = unsafe { &mut *super::pt::ROOT };
-            let Some(page_table) = page_table.table(page >> 10) else {
-                consecutive_pages += 1024;
-                continue;
-            };
-            if page_table[page & 0x3FF].free() {
-                consecutive_pages += 1;
+            if mapper.translate(page).is_some() {
+                page_start += consecutive_pages + run;
+                consecutive_pages = 0;
                 continue;
             }
 
-            page_start += 1 + consecutive_pages;
-            consecutive_pages = 0;
+            consecutive_pages += run;
         }
 
-        Some(page_start)
+        Some(VirtPage::new(page_start))
     }
 }
 
 unsafe impl alloc::GlobalAlloc for VirtualMemory {
     unsafe fn alloc(&self, layout: alloc::Layout) -> *mut u8 {
-        let pages = ((layout.size() - 1) >> 12) + 1;
-        self.allocate(pages)
-            .map_or(ptr::null_mut(), |page_start| (page_start << 12) as *mut u8)
+        // whole-page requests are cheaper to hand out directly than to route
+        // through the heap's free list
+        if layout.size() >= 1 << 12 {
+            let pages = ((layout.size() - 1) >> 12) + 1;
+            return self.allocate(pages).map_or(ptr::null_mut(), |page_start| {
+                page_start.start_address().as_usize() as *mut u8
+            });
+        }
+
+        HEAP.lock().alloc(self, layout)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: alloc::Layout) {
-        let page_start = ((ptr as usize - 1) >> 12) + 1;
+        let mut heap = HEAP.lock();
+        if heap.owns(ptr) {
+            heap.dealloc(ptr, layout);
+            return;
+        }
+        drop(heap);
+
+        let page_start = VirtAddr::new(ptr as usize).containing_page();
         let pages = ((layout.size() - 1) >> 12) + 1;
         self.free(page_start, pages);
     }
@@ -123,26 +251,39 @@ impl acpi::AcpiHandler for VirtualMemory {
         phys_addr: usize,
         size: usize,
     ) -> acpi::PhysicalMapping<Self, T> {
-        let virt_addr = if phys_addr <= 0x003F_FFFF {
-            phys_addr
+        let phys_addr = PhysAddr::new(phys_addr);
+        let virt_addr = if phys_addr.as_usize() <= 0x003F_FFFF {
+            VirtAddr::new(phys_addr.as_usize())
         } else {
-            let offset = phys_addr % super::pt::GRANULARITY;
-            let page = self
-                .map(
-                    phys_addr / super::pt::GRANULARITY,
-                    size.div_ceil(super::pt::GRANULARITY),
-                )
-                .unwrap();
-            page * super::pt::GRANULARITY + offset
+            let pages = size.div_ceil(super::pt::GRANULARITY);
+            let offset = phys_addr.as_usize() % super::pt::GRANULARITY;
+            let page = self.map(phys_addr.containing_frame(), pages).unwrap();
+            let virt_addr = VirtAddr::new(page.start_address().as_usize() + offset);
+
+            ACPI_MAPPINGS.lock().insert(virt_addr.as_usize(), (page, pages));
+            virt_addr
         };
         acpi::PhysicalMapping::new(
-            phys_addr,
-            ptr::NonNull::new_unchecked((virt_addr) as *mut T),
+            phys_addr.as_usize(),
+            ptr::NonNull::new_unchecked((virt_addr.as_usize()) as *mut T),
             size,
             size,
             Self,
         )
     }
 
-    fn unmap_physical_region<T>(_region: &acpi::PhysicalMapping<Self, T>) {}
+    /// Frees the pages a prior `map_physical_region` call allocated,
+    /// leaving the low identity-mapped region (which owns no pages of its
+    /// own) untouched.
+    fn unmap_physical_region<T>(region: &acpi::PhysicalMapping<Self, T>) {
+        let virt_addr = region.virtual_start().as_ptr() as usize;
+        if let Some((page_start, pages)) = ACPI_MAPPINGS.lock().remove(&virt_addr) {
+            // only the pages are released: the frames behind them were never
+            // reserved from `PHYS_MEM`, so they must not be handed back to it
+            let mut mapper = MAPPER.lock();
+            for page in page_start.index()..page_start.index() + pages {
+                mapper.unmap(VirtPage::new(page));
+            }
+        }
+    }
 }